@@ -8,12 +8,20 @@ use crate::{
 use env_logger::Builder;
 use log::LevelFilter;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, path::PathBuf};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    io::{BufRead, BufWriter, Stdout, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
 
 pub trait MessageDispatcher {
     fn was_environment_reported(&self, env: &PythonEnv) -> bool;
     fn report_environment_manager(&mut self, env: EnvManager) -> ();
     fn report_environment(&mut self, env: PythonEnvironment) -> ();
+    fn report_diagnostic(&mut self, diag: Diagnostic) -> ();
     fn exit(&mut self) -> ();
 }
 
@@ -23,6 +31,9 @@ pub trait MessageDispatcher {
 pub enum EnvManagerType {
     Conda,
     Pyenv,
+    Poetry,
+    Hatch,
+    Pdm,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -85,6 +96,9 @@ pub enum PythonEnvironmentCategory {
     WindowsStore,
     WindowsRegistry,
     Pipenv,
+    Poetry,
+    Hatch,
+    Pdm,
     VirtualEnvWrapper,
     Venv,
     VirtualEnv,
@@ -103,7 +117,7 @@ pub struct PythonEnvironment {
     pub env_manager: Option<EnvManager>,
     pub python_run_command: Option<Vec<String>>,
     /**
-     * The project path for the Pipenv environment.
+     * The project path for a project-scoped environment (Pipenv, Poetry, Hatch, Pdm).
      */
     pub project_path: Option<PathBuf>,
 }
@@ -138,11 +152,35 @@ impl PythonEnvironment {
         sys_prefix_path: Option<PathBuf>,
         env_manager: Option<EnvManager>,
         project_path: PathBuf,
+    ) -> Self {
+        Self::new_project_env(
+            PythonEnvironmentCategory::Pipenv,
+            python_executable_path,
+            version,
+            env_path,
+            sys_prefix_path,
+            env_manager,
+            project_path,
+        )
+    }
+
+    /**
+     * Constructs a project-scoped environment (Pipenv, Poetry, Hatch, Pdm, ...) whose interpreter
+     * is resolved relative to `project_path` and a tool executable rather than a fixed location.
+     */
+    pub fn new_project_env(
+        category: PythonEnvironmentCategory,
+        python_executable_path: Option<PathBuf>,
+        version: Option<String>,
+        env_path: Option<PathBuf>,
+        sys_prefix_path: Option<PathBuf>,
+        env_manager: Option<EnvManager>,
+        project_path: PathBuf,
     ) -> Self {
         Self {
             name: None,
             python_executable_path: python_executable_path.clone(),
-            category: PythonEnvironmentCategory::Pipenv,
+            category,
             version,
             env_path,
             sys_prefix_path,
@@ -194,17 +232,308 @@ impl ExitMessage {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+#[derive(Debug)]
+pub enum DiagnosticCode {
+    InvalidManager,
+    MissingExecutable,
+    PermissionDenied,
+    VersionParseFailed,
+    BrokenSymlink,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+#[derive(Debug)]
+pub enum DiagnosticSeverity {
+    Error,
+    Warning,
+    Hint,
+}
+
+/**
+ * A machine-readable problem surfaced during discovery (e.g. a corrupt conda `environments.txt`
+ * or an unreadable pyenv version dir) that the extension can act on, instead of a log string.
+ */
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub code: DiagnosticCode,
+    pub message: String,
+    pub path: Option<PathBuf>,
+    pub env_manager_type: Option<EnvManagerType>,
+    pub severity: DiagnosticSeverity,
+}
+
+impl Diagnostic {
+    pub fn new(
+        code: DiagnosticCode,
+        message: impl Into<String>,
+        path: Option<PathBuf>,
+        env_manager_type: Option<EnvManagerType>,
+        severity: DiagnosticSeverity,
+    ) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            path,
+            env_manager_type,
+            severity,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[derive(Debug)]
+pub struct DiagnosticMessage {
+    pub jsonrpc: String,
+    pub method: String,
+    pub params: Diagnostic,
+}
+
+impl DiagnosticMessage {
+    pub fn new(params: Diagnostic) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            method: "diagnostic".to_string(),
+            params,
+        }
+    }
+}
+
+/**
+ * A request sent to us by the extension over stdin, e.g. `refresh`, `resolve`, `shutdown`.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonRpcRequest {
+    pub jsonrpc: String,
+    pub method: String,
+    pub params: Value,
+    pub id: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/**
+ * The reply to a `JsonRpcRequest`, correlated back to the caller via `id`.
+ * Exactly one of `result`/`error` is set, matching the JSON-RPC 2.0 spec.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonRpcResponse<T> {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl<T> JsonRpcResponse<T> {
+    pub fn success(id: Value, result: T) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+    pub fn failure(id: Value, error: RpcError) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
+/**
+ * A request handler gets mutable access to the `JsonRpcDispatcher` itself, so methods like
+ * `refresh`/`resolve` can consult or update its reported-environment state.
+ */
+pub type RpcHandler = Box<dyn Fn(Value, &mut JsonRpcDispatcher) -> Result<Value, RpcError>>;
+
 pub struct JsonRpcDispatcher {
     pub reported_managers: HashSet<String>,
     pub reported_environments: HashSet<String>,
+    pub reported_diagnostics: HashSet<String>,
+    pub handlers: HashMap<String, RpcHandler>,
+    /**
+     * Set once a `shutdown` request has been dispatched; `start_request_loop` checks this after
+     * every dispatch and exits the read loop when it's set.
+     */
+    pub shutdown_requested: bool,
 }
-pub fn send_message<T: serde::Serialize>(message: T) -> () {
-    let message = serde_json::to_string(&message).unwrap();
-    print!(
+
+impl JsonRpcDispatcher {
+    /**
+     * Registers a handler for an incoming request `method` (e.g. `refresh`, `resolve`, `shutdown`).
+     */
+    pub fn register_handler(&mut self, method: impl Into<String>, handler: RpcHandler) {
+        self.handlers.insert(method.into(), handler);
+    }
+
+    /**
+     * Flushes the buffered stdout writer, ensuring every message written so far has actually
+     * reached the extension rather than sitting in the `BufWriter`.
+     */
+    pub fn flush(&self) {
+        flush_messages();
+    }
+
+    /**
+     * Serializes and writes a run of new environments under a single lock acquisition, to cut
+     * syscall overhead when a locator yields many environments at once. Managers are still
+     * reported (and deduped) individually via `report_environment_manager`.
+     */
+    pub fn report_environments_batch(&mut self, envs: Vec<PythonEnvironment>) {
+        let mut managers = Vec::new();
+        {
+            let mut out = writer().lock().unwrap();
+            for env in envs {
+                if let Some(key) = get_environment_key(&env) {
+                    if !self.reported_environments.contains(&key) {
+                        self.reported_environments.insert(key);
+                        let manager = env.env_manager.clone();
+                        let message =
+                            serde_json::to_string(&PythonEnvironmentMessage::new(env)).unwrap();
+                        write_framed(&mut out, &message);
+                        if let Some(manager) = manager {
+                            managers.push(manager);
+                        }
+                    }
+                }
+            }
+        }
+        for manager in managers {
+            self.report_environment_manager(manager);
+        }
+    }
+
+    /**
+     * Dispatches a single incoming request to its registered handler and writes back a
+     * `JsonRpcResponse` correlated to the request's `id`, flushing it immediately so a caller
+     * blocked awaiting the reply isn't left waiting on a buffered write. Unknown methods get a
+     * `MethodNotFound` error response rather than being silently dropped. A `shutdown` request
+     * always marks `shutdown_requested`, regardless of whether a handler is registered for it,
+     * so `start_request_loop` can stop reading.
+     */
+    pub fn dispatch_request(&mut self, request: JsonRpcRequest) {
+        // Handlers need `&mut self` (e.g. `refresh` updates reported-environment state), so the
+        // handler is taken out of the map for the call and reinserted afterwards to avoid
+        // borrowing `self.handlers` and `self` mutably at the same time.
+        let handler = self.handlers.remove(&request.method);
+        match &handler {
+            Some(handler) => match handler(request.params, self) {
+                Ok(result) => send_message(JsonRpcResponse::success(request.id, result)),
+                Err(err) => send_message(JsonRpcResponse::<Value>::failure(request.id, err)),
+            },
+            None => send_message(JsonRpcResponse::<Value>::failure(
+                request.id,
+                RpcError::new(-32601, format!("Method not found: {}", request.method)),
+            )),
+        }
+        if let Some(handler) = handler {
+            self.handlers.insert(request.method.clone(), handler);
+        }
+        if request.method == "shutdown" {
+            self.shutdown_requested = true;
+        }
+        self.flush();
+    }
+}
+
+/**
+ * Reads `Content-Length`/`Content-Type` framed JSON-RPC requests from `reader` in a loop,
+ * dispatching each one to `dispatcher` until stdin is closed or a `shutdown` request has been
+ * dispatched. A read error, a frame without a `Content-Length` header, or a body shorter than its
+ * `Content-Length` also ends the loop. Mirrors the framing `send_message` writes, so this is the
+ * read-side counterpart of the protocol the extension speaks to us.
+ */
+pub fn start_request_loop<R: BufRead>(mut reader: R, dispatcher: &mut JsonRpcDispatcher) {
+    loop {
+        let mut content_length: Option<usize> = None;
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) => return,
+                Ok(_) => {}
+                Err(_) => return,
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length:") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+
+        let Some(length) = content_length else {
+            return;
+        };
+        let mut body = vec![0u8; length];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+
+        match serde_json::from_slice::<JsonRpcRequest>(&body) {
+            Ok(request) => dispatcher.dispatch_request(request),
+            Err(err) => log::error!("Failed to parse incoming JSON-RPC request: {}", err),
+        }
+
+        if dispatcher.shutdown_requested {
+            return;
+        }
+    }
+}
+
+/**
+ * The single owned stdout writer, buffered and guarded by a mutex so that concurrent discovery
+ * threads reporting environments can't interleave `Content-Length` frames.
+ */
+fn writer() -> &'static Mutex<BufWriter<Stdout>> {
+    static WRITER: OnceLock<Mutex<BufWriter<Stdout>>> = OnceLock::new();
+    WRITER.get_or_init(|| Mutex::new(BufWriter::new(std::io::stdout())))
+}
+
+fn write_framed(out: &mut BufWriter<Stdout>, message: &str) {
+    write!(
+        out,
         "Content-Length: {}\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\r\n{}",
         message.len(),
         message
-    );
+    )
+    .unwrap();
+}
+
+pub fn send_message<T: serde::Serialize>(message: T) -> () {
+    let message = serde_json::to_string(&message).unwrap();
+    let mut out = writer().lock().unwrap();
+    write_framed(&mut out, &message);
+}
+
+pub fn flush_messages() {
+    writer().lock().unwrap().flush().unwrap();
 }
 
 pub fn initialize_logger(log_level: LevelFilter) {
@@ -227,7 +556,6 @@ pub fn initialize_logger(log_level: LevelFilter) {
         .init();
 }
 
-impl JsonRpcDispatcher {}
 impl MessageDispatcher for JsonRpcDispatcher {
     fn was_environment_reported(&self, env: &PythonEnv) -> bool {
         if let Some(key) = env.executable.as_os_str().to_str() {
@@ -255,8 +583,17 @@ impl MessageDispatcher for JsonRpcDispatcher {
             }
         }
     }
+    fn report_diagnostic(&mut self, diag: Diagnostic) -> () {
+        if let Some(key) = get_diagnostic_key(&diag) {
+            if !self.reported_diagnostics.contains(&key) {
+                self.reported_diagnostics.insert(key);
+                send_message(DiagnosticMessage::new(diag));
+            }
+        }
+    }
     fn exit(&mut self) -> () {
         send_message(ExitMessage::new());
+        self.flush();
     }
 }
 
@@ -264,19 +601,192 @@ pub fn create_dispatcher() -> JsonRpcDispatcher {
     JsonRpcDispatcher {
         reported_managers: HashSet::new(),
         reported_environments: HashSet::new(),
+        reported_diagnostics: HashSet::new(),
+        handlers: HashMap::new(),
+        shutdown_requested: false,
     }
 }
 
 fn get_environment_key(env: &PythonEnvironment) -> Option<String> {
-    match env.python_executable_path.clone() {
+    let base = match env.python_executable_path.clone() {
         Some(key) => Some(key.as_os_str().to_str()?.to_string()),
         None => match env.env_path.clone() {
             Some(key) => Some(key.as_os_str().to_str().unwrap().to_string()),
             None => None,
         },
+    }?;
+    // Project-scoped environments (Pipenv, Poetry, Hatch, Pdm) can share a base interpreter
+    // path across projects, so fold the project path and category into the key too.
+    match env.project_path.clone() {
+        Some(project_path) => Some(format!(
+            "{}:{}:{:?}",
+            base,
+            project_path.to_string_lossy(),
+            env.category
+        )),
+        None => Some(base),
     }
 }
 
 fn get_manager_key(manager: &EnvManager) -> Option<String> {
     Some(manager.executable_path.to_str()?.to_string())
+}
+
+fn get_diagnostic_key(diag: &Diagnostic) -> Option<String> {
+    let path = diag
+        .path
+        .as_ref()
+        .and_then(|p| p.to_str())
+        .unwrap_or_default();
+    Some(format!("{:?}:{}", diag.code, path))
+}
+
+/**
+ * A single named source of environments declared in a `DiscoveryConfig`: extra directories to
+ * scan, explicit environments to always report, and whether this source is active at all.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvSourceConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub search_paths: Vec<PathBuf>,
+    #[serde(default)]
+    pub environments: Vec<PythonEnvironment>,
+    /**
+     * Name of a manager declared in `DiscoveryConfig::managers` that owns the environments
+     * above, e.g. to attribute a custom conda install. Validated at load time.
+     */
+    pub env_manager: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/**
+ * User/workspace config augmenting auto-detection: named sources of extra environments plus the
+ * managers those sources may reference. Declared environments still flow through the dispatcher's
+ * normal `report_environment` dedup path, so they coexist with auto-detected ones.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DiscoveryConfig {
+    #[serde(default)]
+    pub managers: HashMap<String, EnvManager>,
+    #[serde(default)]
+    pub sources: HashMap<String, EnvSourceConfig>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String),
+    Parse(String),
+    UnknownManager(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(msg) => write!(f, "Failed to read discovery config: {}", msg),
+            ConfigError::Parse(msg) => write!(f, "Failed to parse discovery config: {}", msg),
+            ConfigError::UnknownManager(name) => write!(
+                f,
+                "Discovery config references undeclared env_manager '{}'",
+                name
+            ),
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    /**
+     * Loads a `DiscoveryConfig` from a JSON file and validates that every source's `env_manager`
+     * names a manager declared in `managers`, returning a `ConfigError::UnknownManager` listing
+     * the missing name otherwise.
+     */
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|e| ConfigError::Io(e.to_string()))?;
+        let config: DiscoveryConfig =
+            serde_json::from_str(&contents).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for source in self.sources.values() {
+            if let Some(name) = &source.env_manager {
+                if !self.managers.contains_key(name) {
+                    return Err(ConfigError::UnknownManager(name.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Feeds every enabled source's declared environments, plus interpreters found by scanning
+     * its `search_paths`, through `dispatcher`'s normal `report_environment` dedup path.
+     * Environments that don't already name a manager are attributed to the source's validated
+     * `env_manager`, so it gets reported (and deduped) alongside them.
+     */
+    pub fn report_declared_environments(&self, dispatcher: &mut impl MessageDispatcher) {
+        for source in self.sources.values() {
+            if !source.enabled {
+                continue;
+            }
+            let manager = source
+                .env_manager
+                .as_ref()
+                .and_then(|name| self.managers.get(name))
+                .cloned();
+            for env in &source.environments {
+                let mut env = env.clone();
+                if env.env_manager.is_none() {
+                    env.env_manager = manager.clone();
+                }
+                dispatcher.report_environment(env);
+            }
+            for search_path in &source.search_paths {
+                for env in scan_search_path(search_path, manager.clone()) {
+                    dispatcher.report_environment(env);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Scans `dir` (non-recursively) for `python`/`python3`/`python.exe` executables and returns each
+ * as a bare `System`-category environment attributed to `manager`, for user-declared extra
+ * directories the native locator wouldn't otherwise find.
+ */
+fn scan_search_path(dir: &Path, manager: Option<EnvManager>) -> Vec<PythonEnvironment> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && matches!(
+                    path.file_name().and_then(|n| n.to_str()),
+                    Some("python") | Some("python3") | Some("python.exe")
+                )
+        })
+        .map(|path| {
+            PythonEnvironment::new(
+                None,
+                Some(path.clone()),
+                PythonEnvironmentCategory::System,
+                None,
+                None,
+                None,
+                manager.clone(),
+                Some(vec![path.to_string_lossy().to_string()]),
+            )
+        })
+        .collect()
 }
\ No newline at end of file